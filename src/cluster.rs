@@ -0,0 +1,334 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Opcodes of the client request frame (`[opcode][key][value]`), mirrored
+/// here so replication can forward a command without depending on the
+/// server's private `Command` type.
+pub const OPCODE_READ: u8 = 0;
+pub const OPCODE_WRITE: u8 = 2;
+pub const OPCODE_DELETE: u8 = 3;
+
+/// Opcode for the in-cluster `FIND_NODE` RPC, speaking the same binary
+/// framing as client requests now that the retired `\r`-terminated text
+/// protocol is gone.
+pub const OPCODE_FIND_NODE: u8 = 8;
+
+/// Status bytes of the response frame (`[status][length][payload]`),
+/// mirrored from the server's private `Status` enum for the same reason.
+pub const STATUS_OK: u8 = 0;
+pub const STATUS_VERIFICATION_FAILED: u8 = 2;
+
+/// Width of the Kademlia ID space in bits; a node's ID and a key's position
+/// are both `Sha256` digests, so they live in the same 256-bit space.
+pub const ID_BITS: usize = 256;
+
+/// Bucket size: each k-bucket holds at most this many known peers.
+pub const K: usize = 16;
+
+/// Lookup parallelism: number of peers queried concurrently per round.
+pub const ALPHA: usize = 3;
+
+/// Upper bound on rounds for an iterative `FIND_NODE` lookup.
+pub const MAX_LOOKUP_ROUNDS: usize = 8;
+
+pub type NodeId = [u8; 32];
+
+/// Maps a storage key to its position in the 256-bit ID space, reusing the
+/// same digest `Shard` already hashes keys with.
+pub fn node_id_for_key(key: &[u8]) -> NodeId {
+    Sha256::digest(key).into()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Peer {
+    pub id: NodeId,
+    pub addr: SocketAddr,
+}
+
+/// XOR distance metric between two IDs.
+fn distance(a: &NodeId, b: &NodeId) -> NodeId {
+    let mut out = [0u8; 32];
+    for i in 0..32 {
+        out[i] = a[i] ^ b[i];
+    }
+    out
+}
+
+/// Number of leading zero bits in an ID, i.e. how many leading bits two IDs
+/// share when computed over their XOR distance.
+fn leading_zero_bits(id: &NodeId) -> usize {
+    for (i, byte) in id.iter().enumerate() {
+        if *byte != 0 {
+            return i * 8 + byte.leading_zeros() as usize;
+        }
+    }
+    ID_BITS
+}
+
+/// A Kademlia routing table: `ID_BITS` k-buckets, bucket `i` holding peers
+/// whose ID shares its first `i` bits with ours (indexed by the highest
+/// differing bit between the two IDs).
+pub struct RoutingTable {
+    local_id: NodeId,
+    buckets: Vec<Vec<Peer>>,
+}
+
+impl RoutingTable {
+    pub fn new(local_id: NodeId) -> Self {
+        RoutingTable {
+            local_id,
+            buckets: (0..ID_BITS).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    fn bucket_index(&self, id: &NodeId) -> usize {
+        leading_zero_bits(&distance(&self.local_id, id)).min(ID_BITS - 1)
+    }
+
+    /// Records a peer was seen, dropping it if its bucket is already full of
+    /// `K` peers (the baseline Kademlia refresh would ping the least-recently
+    /// seen entry first; this keeps the first `K` peers learned per bucket).
+    pub fn observe(&mut self, peer: Peer) {
+        if peer.id == self.local_id {
+            return;
+        }
+        let idx = self.bucket_index(&peer.id);
+        let bucket = &mut self.buckets[idx];
+        if let Some(existing) = bucket.iter_mut().find(|p| p.id == peer.id) {
+            *existing = peer;
+            return;
+        }
+        if bucket.len() < K {
+            bucket.push(peer);
+        } else {
+            debug!("Dropping peer, bucket full: {:?}", peer.id);
+        }
+    }
+
+    /// Returns up to `count` known peers closest to `target` by XOR distance.
+    pub fn closest(&self, target: &NodeId, count: usize) -> Vec<Peer> {
+        let mut all: Vec<Peer> = self.buckets.iter().flatten().copied().collect();
+        all.sort_by_key(|p| distance(&p.id, target));
+        all.truncate(count);
+        all
+    }
+
+    pub fn local_id(&self) -> NodeId {
+        self.local_id
+    }
+}
+
+/// Runs an iterative `FIND_NODE` lookup for `target`, converging on the `K`
+/// closest known nodes.
+///
+/// Queries the `ALPHA` closest not-yet-queried peers from the current
+/// shortlist each round, merges their responses in, and stops once a round
+/// yields no peer that was not already on the shortlist, once every
+/// shortlisted peer has been queried, or after `MAX_LOOKUP_ROUNDS`.
+pub async fn find_node(table: &RoutingTable, target: NodeId, access_key: &[u8]) -> Vec<Peer> {
+    let mut shortlist = table.closest(&target, K);
+    let mut queried: HashSet<NodeId> = HashSet::new();
+
+    for _ in 0..MAX_LOOKUP_ROUNDS {
+        let to_query: Vec<Peer> = shortlist
+            .iter()
+            .filter(|p| !queried.contains(&p.id))
+            .take(ALPHA)
+            .copied()
+            .collect();
+
+        if to_query.is_empty() {
+            break;
+        }
+
+        let mut progressed = false;
+        for peer in to_query {
+            queried.insert(peer.id);
+            let found = rpc_find_node(peer, target, access_key).await.unwrap_or_default();
+            for candidate in found {
+                if candidate.id != table.local_id() && !shortlist.iter().any(|p| p.id == candidate.id) {
+                    shortlist.push(candidate);
+                    progressed = true;
+                }
+            }
+        }
+
+        shortlist.sort_by_key(|p| distance(&p.id, &target));
+        shortlist.truncate(K);
+
+        if !progressed {
+            break;
+        }
+    }
+
+    shortlist
+}
+
+/// Sends a binary `OPCODE_FIND_NODE` request (`target` as the key, no
+/// value) to `peer` and parses the `peer_id@ip:port,...` response payload.
+async fn rpc_find_node(peer: Peer, target: NodeId, access_key: &[u8]) -> std::io::Result<Vec<Peer>> {
+    let (status, payload) = call_peer(peer, access_key, OPCODE_FIND_NODE, &target, &[]).await?;
+    if status != STATUS_OK {
+        return Ok(Vec::new());
+    }
+    Ok(parse_peer_list(&String::from_utf8_lossy(&payload)))
+}
+
+/// Parses a `id_hex@ip:port,...` peer list, the wire format used both for
+/// `find_node` responses and the `FFFFFF_CLUSTER_PEERS` bootstrap list.
+pub(crate) fn parse_peer_list(raw: &str) -> Vec<Peer> {
+    raw.trim()
+        .split(',')
+        .filter_map(|entry| {
+            let (id_hex, addr) = entry.split_once('@')?;
+            let id_bytes = hex::decode(id_hex).ok()?;
+            let id: NodeId = id_bytes.try_into().ok()?;
+            let addr: SocketAddr = addr.parse().ok()?;
+            Some(Peer { id, addr })
+        })
+        .collect()
+}
+
+/// Inverse of `parse_peer_list`, used by the server's `find_node` handler to
+/// encode its response payload.
+pub fn encode_peer_list(peers: &[Peer]) -> Vec<u8> {
+    peers
+        .iter()
+        .map(|p| format!("{}@{}", hex::encode(p.id), p.addr))
+        .collect::<Vec<_>>()
+        .join(",")
+        .into_bytes()
+}
+
+async fn write_frame(
+    write_half: &mut OwnedWriteHalf,
+    opcode: u8,
+    key: &[u8],
+    value: &[u8],
+) -> std::io::Result<()> {
+    let mut frame = Vec::with_capacity(1 + 4 + key.len() + 4 + value.len());
+    frame.push(opcode);
+    frame.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    frame.extend_from_slice(key);
+    frame.extend_from_slice(&(value.len() as u32).to_be_bytes());
+    frame.extend_from_slice(value);
+    write_half.write_all(&frame).await
+}
+
+async fn read_response(
+    reader: &mut BufReader<tokio::net::tcp::OwnedReadHalf>,
+) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut status = [0u8; 1];
+    reader.read_exact(&mut status).await?;
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let mut payload = vec![0u8; u32::from_be_bytes(len_buf) as usize];
+    reader.read_exact(&mut payload).await?;
+    Ok((status[0], payload))
+}
+
+/// Connects to `peer`, authenticates with `access_key` exactly like a
+/// regular client would (`handle_connection` requires the first frame on
+/// every connection, cluster-internal or not, to carry a valid access key),
+/// then sends `[opcode][key][value]` and returns the peer's
+/// `(status, payload)` response.
+async fn call_peer(
+    peer: Peer,
+    access_key: &[u8],
+    opcode: u8,
+    key: &[u8],
+    value: &[u8],
+) -> std::io::Result<(u8, Vec<u8>)> {
+    let stream = TcpStream::connect(peer.addr).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    write_frame(&mut write_half, 0, access_key, &[]).await?;
+    let (auth_status, _) = read_response(&mut reader).await?;
+    if auth_status != STATUS_OK {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "peer rejected cluster access key",
+        ));
+    }
+
+    write_frame(&mut write_half, opcode, key, value).await?;
+    read_response(&mut reader).await
+}
+
+/// Forwards a `read`/`write`/`delete` request to a peer that owns the key,
+/// authenticating first with the cluster's shared access key, then speaking
+/// the same `[opcode][4-byte key length][key][4-byte value length][value]`
+/// frame the client protocol uses. Returns the peer's `(status, payload)`
+/// response.
+pub async fn forward_command(
+    peer: Peer,
+    access_key: &[u8],
+    opcode: u8,
+    key: &[u8],
+    value: &[u8],
+) -> std::io::Result<(u8, Vec<u8>)> {
+    call_peer(peer, access_key, opcode, key, value).await
+}
+
+/// Per-node clustering configuration threaded through `Storage`.
+pub struct ClusterConfig {
+    pub local_id: NodeId,
+    /// This node's own address, so it can be compared for proximity against
+    /// remote peers in `replicas_for` instead of being structurally excluded.
+    pub local_addr: SocketAddr,
+    pub routing_table: RoutingTable,
+    pub replication_factor: usize,
+    /// Access key this node presents when it connects to a peer, e.g. to
+    /// forward a write or run a `find_node` RPC. Must also be configured
+    /// with `rw` in every peer's `FFFFFF_ACCESS_KEYS`.
+    pub access_key: Vec<u8>,
+}
+
+impl ClusterConfig {
+    pub fn new(
+        local_id: NodeId,
+        local_addr: SocketAddr,
+        bootstrap_peers: Vec<Peer>,
+        replication_factor: usize,
+        access_key: Vec<u8>,
+    ) -> Self {
+        let mut routing_table = RoutingTable::new(local_id);
+        for peer in bootstrap_peers {
+            routing_table.observe(peer);
+        }
+        ClusterConfig {
+            local_id,
+            local_addr,
+            routing_table,
+            replication_factor,
+            access_key,
+        }
+    }
+
+    /// The nodes that should hold a replica of `key`, ordered by proximity
+    /// to `Sha256(key)`.
+    ///
+    /// `RoutingTable`/`find_node` structurally exclude the local ID (a node
+    /// never adds itself to its own buckets), so the local node is added
+    /// back in here as a candidate and ranked by the same XOR distance as
+    /// everyone else before truncating to the replication factor — otherwise
+    /// a two-node cluster would forward every key to the other node forever,
+    /// each treating the other as the only possible replica.
+    pub async fn replicas_for(&self, key: &[u8]) -> Vec<Peer> {
+        let target = node_id_for_key(key);
+        let mut candidates = find_node(&self.routing_table, target, &self.access_key).await;
+        candidates.push(Peer {
+            id: self.local_id,
+            addr: self.local_addr,
+        });
+        candidates.sort_by_key(|p| distance(&p.id, &target));
+        candidates.truncate(self.replication_factor);
+        candidates
+    }
+}
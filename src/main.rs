@@ -1,48 +1,263 @@
 mod storage;
+mod secure_stream;
+mod cluster;
 
 use std::sync::Arc;
 use papaya::HashMap;
 
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use secure_stream::SecureStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
 use tokio::net::*;
 use tokio::sync::{Mutex, RwLock};
-use tracing::{Level, debug};
+use tracing::{Level, debug, warn};
 use tracing_subscriber::FmtSubscriber;
 use tokio::signal;
 
-enum Response<T>{
-    Success(T),
-    Failure,
+/// Maximum key length accepted in a request frame.
+const MAX_KEY_LEN: u32 = 1024;
+
+/// Maximum value length accepted in a request frame, so a 4-byte length
+/// prefix on the plaintext transport can't force a multi-gigabyte
+/// allocation before the rest of the frame has even arrived.
+const MAX_VALUE_LEN: u32 = 16 * 1024 * 1024;
+
+/// Reads whether newly accepted connections must complete the X25519
+/// handshake before the command loop starts from the `FFFFFF_REQUIRE_ENCRYPTION`
+/// env var (`1`/`true` to require it). Defaults to `false` (plaintext) when
+/// the env var is unset or holds any other value.
+fn require_encrypted_transport() -> bool {
+    match std::env::var("FFFFFF_REQUIRE_ENCRYPTION") {
+        Ok(raw) => matches!(raw.trim(), "1" | "true"),
+        Err(_) => false,
+    }
+}
+
+/// What a connection authenticated with a given access key is allowed to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Capability {
+    ReadOnly,
+    ReadWrite,
+}
+
+/// Access keys accepted by the server, keyed by the literal key bytes sent
+/// as the first framed message on a connection.
+struct AccessKeys {
+    keys: std::collections::HashMap<String, Capability>,
+}
+
+impl AccessKeys {
+    /// Loads the allowed access keys from the `FFFFFF_ACCESS_KEYS` env var,
+    /// formatted as comma-separated `key:ro` / `key:rw` pairs.
+    fn load() -> Self {
+        let mut keys = std::collections::HashMap::new();
+
+        if let Ok(raw) = std::env::var("FFFFFF_ACCESS_KEYS") {
+            for entry in raw.split(',') {
+                let entry = entry.trim();
+                if entry.is_empty() {
+                    continue;
+                }
+                match entry.split_once(':') {
+                    Some((key, "rw")) => {
+                        keys.insert(key.to_string(), Capability::ReadWrite);
+                    }
+                    Some((key, "ro")) => {
+                        keys.insert(key.to_string(), Capability::ReadOnly);
+                    }
+                    _ => {
+                        debug!("Ignoring malformed access key entry: ~{}~", entry);
+                    }
+                }
+            }
+        }
+
+        if keys.is_empty() {
+            warn!("FFFFFF_ACCESS_KEYS is unset or empty; every connection will fail authentication");
+        }
+
+        AccessKeys { keys }
+    }
+
+    fn capability_for(&self, key: &str) -> Option<Capability> {
+        self.keys.get(key).copied()
+    }
+}
+
+/// A decoded request frame: `[1-byte opcode][4-byte key length][key bytes]
+/// [4-byte value length][value bytes]`. Neither `key` nor `value` is
+/// assumed to be UTF-8.
+struct Request {
+    opcode: u8,
+    key: Vec<u8>,
+    value: Vec<u8>,
+}
+
+/// Reads one request frame from a plain byte stream.
+async fn read_request_frame<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut R,
+) -> std::io::Result<Request> {
+    let mut opcode = [0u8; 1];
+    reader.read_exact(&mut opcode).await?;
+
+    let key = read_length_prefixed(reader, MAX_KEY_LEN).await?;
+    let value = read_length_prefixed(reader, MAX_VALUE_LEN).await?;
+
+    Ok(Request {
+        opcode: opcode[0],
+        key,
+        value,
+    })
+}
+
+async fn read_length_prefixed<R: tokio::io::AsyncRead + Unpin>(
+    reader: &mut R,
+    max_len: u32,
+) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf).await?;
+    let len = u32::from_be_bytes(len_buf);
+
+    if len > max_len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame field exceeds maximum length",
+        ));
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf)
+}
+
+/// Decodes a request frame already fully read into memory (used for the
+/// `SecureStream` path, where one AEAD frame carries one request).
+fn decode_request(buf: &[u8]) -> std::io::Result<Request> {
+    if buf.len() < 9 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "frame shorter than the minimum request header",
+        ));
+    }
+
+    let opcode = buf[0];
+    let key_len = u32::from_be_bytes(buf[1..5].try_into().unwrap());
+    if key_len > MAX_KEY_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "key length exceeds maximum",
+        ));
+    }
+
+    let key_start = 5;
+    let key_end = key_start + key_len as usize;
+    let value_len_end = key_end + 4;
+    if buf.len() < value_len_end {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "truncated request frame",
+        ));
+    }
+
+    let key = buf[key_start..key_end].to_vec();
+    let value_len = u32::from_be_bytes(buf[key_end..value_len_end].try_into().unwrap());
+    let value_end = value_len_end + value_len as usize;
+    if buf.len() < value_end {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "truncated request frame",
+        ));
+    }
+
+    Ok(Request {
+        opcode,
+        key,
+        value: buf[value_len_end..value_end].to_vec(),
+    })
+}
+
+/// Unifies the plaintext and handshake-derived transports so the command
+/// loop can read/write a request without caring which one is in use.
+enum Transport {
+    Plain {
+        reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+        writer: tokio::net::tcp::OwnedWriteHalf,
+    },
+    Secure(SecureStream),
 }
 
+impl Transport {
+    async fn read_request(&mut self) -> std::io::Result<Request> {
+        match self {
+            Transport::Plain { reader, .. } => read_request_frame(reader).await,
+            Transport::Secure(stream) => decode_request(&stream.read_frame().await?),
+        }
+    }
 
+    async fn write_response(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Transport::Plain { writer, .. } => writer.write_all(data).await,
+            Transport::Secure(stream) => stream.write_frame(data).await,
+        }
+    }
+}
 
+/// Status byte of a response frame: `[1-byte status][4-byte length][payload]`.
+#[repr(u8)]
+enum Status {
+    Ok = 0,
+    Failure = 1,
+    /// Stored digest didn't match the data read back, as opposed to a plain
+    /// `Failure` (key not found, malformed request, ...).
+    VerificationFailed = 2,
+}
 
+enum Response<T>{
+    Success(T),
+    Failure,
+    VerificationFailed,
+}
 
 impl Response<String>  {
     fn parse(&self) -> Vec<u8> {
-        let mut result = match self {
-            Response::Success(data) => data.to_string().into_bytes(),
-            Response::Failure => "error".as_bytes().to_vec(),
-        };
-        result.push(b'\r');
-        result
+        match self {
+            Response::Success(data) => encode_response(Status::Ok, data.as_bytes()),
+            Response::Failure => encode_response(Status::Failure, &[]),
+            Response::VerificationFailed => encode_response(Status::VerificationFailed, &[]),
+        }
     }
 
 }
 
 impl Response<&[u8]>  {
     fn parse(&self) -> Vec<u8> {
-        let mut result = match self {
-            Response::Success(data) => data.to_vec(),
-            Response::Failure => "error".as_bytes().to_vec(),
-        };
-        result.push(b'\r');
-        result
+        match self {
+            Response::Success(data) => encode_response(Status::Ok, data),
+            Response::Failure => encode_response(Status::Failure, &[]),
+            Response::VerificationFailed => encode_response(Status::VerificationFailed, &[]),
+        }
+    }
+
+}
+
+impl Response<Vec<u8>>  {
+    fn parse(&self) -> Vec<u8> {
+        match self {
+            Response::Success(data) => encode_response(Status::Ok, data),
+            Response::Failure => encode_response(Status::Failure, &[]),
+            Response::VerificationFailed => encode_response(Status::VerificationFailed, &[]),
+        }
     }
 
 }
 
+fn encode_response(status: Status, payload: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(1 + 4 + payload.len());
+    result.push(status as u8);
+    result.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    result.extend_from_slice(payload);
+    result
+}
+
 enum Command {
     Read,
     Reads,
@@ -50,20 +265,29 @@ enum Command {
     Delete,
     Status,
     Keys,
+    Compact,
+    Verify,
+    FindNode,
     Error,
 }
 
 impl  Command {
-    fn from_op(op: &[u8]) -> Command {
-        match String::from_utf8_lossy(&op).replace('\0', "").trim() {
-            "read" => Command::Read,
-            "reads" => Command::Reads,
-            "write" => Command::Write,
-            "delete" => Command::Delete,
-            "status" => Command::Status,
-            "keys" => Command::Keys,
+    /// Maps a request's 1-byte opcode to a `Command`. Opcodes are assigned
+    /// in the order the commands were introduced; `0xff` and anything
+    /// unrecognized decode to `Command::Error`.
+    fn from_op(opcode: u8) -> Command {
+        match opcode {
+            0 => Command::Read,
+            1 => Command::Reads,
+            2 => Command::Write,
+            3 => Command::Delete,
+            4 => Command::Status,
+            5 => Command::Keys,
+            6 => Command::Compact,
+            7 => Command::Verify,
+            8 => Command::FindNode,
             v => {
-                debug!("Unknown command: ~{:?}~", v);
+                debug!("Unknown opcode: {}", v);
                 Command::Error
             },
         }
@@ -71,71 +295,170 @@ impl  Command {
 }
 
 
-async fn handle_connection(socket: TcpStream, state: Arc<RwLock<storage::Storage>>) {
-    let (read_half, mut write_half) = socket.into_split();
-    let mut reader = BufReader::new(read_half);
-
-    loop {
-        let mut buffer = Vec::new();
-        reader.read_until(b'\r', &mut buffer).await.unwrap();
+async fn handle_connection(
+    socket: TcpStream,
+    state: Arc<RwLock<storage::Storage>>,
+    access_keys: Arc<AccessKeys>,
+    require_encrypted_transport: bool,
+) {
+    let (read_half, write_half) = socket.into_split();
 
+    let mut transport = if require_encrypted_transport {
+        match SecureStream::handshake(read_half, write_half).await {
+            Ok(stream) => Transport::Secure(stream),
+            Err(e) => {
+                debug!("Secure handshake failed: {}", e);
+                return;
+            }
+        }
+    } else {
+        Transport::Plain {
+            reader: BufReader::new(read_half),
+            writer: write_half,
+        }
+    };
 
-        if buffer.len() == 0 {
-            debug!("Connection closed");
+    let capability = match transport.read_request().await {
+        Ok(request) => {
+            let key = String::from_utf8_lossy(&request.key).to_string();
+            match access_keys.capability_for(&key) {
+                Some(capability) => {
+                    debug!("Access key accepted");
+                    let _ = transport.write_response(&Response::<String>::Success("ok".into()).parse()).await;
+                    capability
+                }
+                None => {
+                    debug!("Access key rejected");
+                    let _ = transport.write_response(&Response::<String>::Failure.parse()).await;
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            debug!("Connection closed during authentication: {}", e);
             return;
         }
+    };
 
-        match String::from_utf8(buffer.to_vec()).unwrap().split_once(' ')  {
-            Some((op, rest)) => {
-                debug!("Operation: ~{}~", op.trim());
-                debug!("AAAAAAAAa: ~{}~", rest.trim());
-
-                let _ =  match Command::from_op(op.trim().as_bytes()) {
-                    Command::Read =>
-                        match state.read().await.get_by_btree(rest.trim().as_bytes()){
-                            Some(v) => {
-                            debug!("Read key: '{}' value: '{:?}'", rest.trim(), v);
-                            write_half.write(&Response::Success(v).parse()).await.unwrap()
-                        }
-                            None => {
-                                debug!("Read key: '{}' not found", rest.trim());
-                                write_half.write(&Response::<&[u8]>::Failure.parse()).await.unwrap()
-                            }
-                        },
-                    Command::Reads => write_half.write(&Response::<String>::Success("Reads".into()).parse()).await.unwrap(),
-                    Command::Write => {
-                        match rest.trim().split_once("|") {
-                            Some((key, value)) => {
-                                debug!("Writing key: '{}' value: '{}'", key.trim(), value.trim());
-                                state.write().await.add(key.trim().as_bytes(), value.trim().as_bytes()).unwrap();
-                                write_half.write(&Response::<String>::Success("Success".into()).parse()).await.unwrap()
-                            },
-                            None => {
-                                write_half.write(&Response::<String>::Failure.parse()).await.unwrap()
-                            }
-                        }
-                    },
-                    Command::Delete => write_half.write(&Response::<String>::Success("Delete".into()).parse()).await.unwrap(),
-                    Command::Error => write_half.write(&Response::<String>::Success("Error".into()).parse()).await.unwrap(),
-                    _ => write_half.write(&Response::<String>::Failure.parse()).await.unwrap(),
-                };
+    loop {
+        let request = match transport.read_request().await {
+            Ok(request) => request,
+            Err(e) => {
+                debug!("Connection closed: {}", e);
+                return;
             }
-            None => {
-                let _ = match Command::from_op(String::from_utf8(buffer.to_vec()).unwrap().trim().as_bytes()) {
-                    Command::Status => {
-                        write_half.write(&Response::<String>::Success("well going our operation".into()).parse()).await.unwrap()
-                    },
-                    Command::Keys => write_half.write(&Response::<String>::Success("key1,key2,key3".into()).parse()).await.unwrap(),
-                    _ => {
-                        write_half.write(&Response::<String>::Failure.parse()).await.unwrap()
+        };
+
+        let _ = match Command::from_op(request.opcode) {
+            Command::Read =>
+                match state.read().await.get_by_btree(&request.key).await {
+                    storage::GetOutcome::Found(v) => {
+                        debug!("Read key: '{:?}' value: '{:?}'", request.key, v);
+                        transport.write_response(&Response::Success(v).parse()).await.unwrap()
+                    }
+                    storage::GetOutcome::NotFound => {
+                        debug!("Read key: '{:?}' not found", request.key);
+                        transport.write_response(&Response::<Vec<u8>>::Failure.parse()).await.unwrap()
                     }
-                };
+                    storage::GetOutcome::Corrupted => {
+                        debug!("Read key: '{:?}' failed checksum verification", request.key);
+                        transport.write_response(&Response::<Vec<u8>>::VerificationFailed.parse()).await.unwrap()
+                    }
+                },
+            Command::Reads => transport.write_response(&Response::<String>::Success("Reads".into()).parse()).await.unwrap(),
+            Command::Write if capability == Capability::ReadWrite => {
+                debug!("Writing key: '{:?}' value: '{:?}'", request.key, request.value);
+                state.write().await.add(&request.key, &request.value).await.unwrap();
+                transport.write_response(&Response::<String>::Success("Success".into()).parse()).await.unwrap()
             },
-        }
+            Command::Delete if capability == Capability::ReadWrite => {
+                match state.write().await.del(&request.key).await {
+                    Ok(()) => transport.write_response(&Response::<String>::Success("Delete".into()).parse()).await.unwrap(),
+                    Err(e) => {
+                        debug!("Delete failed: {}", e);
+                        transport.write_response(&Response::<String>::Failure.parse()).await.unwrap()
+                    }
+                }
+            },
+            Command::Write | Command::Delete => {
+                debug!("Rejecting write operation for read-only access key");
+                transport.write_response(&Response::<String>::Failure.parse()).await.unwrap()
+            },
+            Command::Status => {
+                transport.write_response(&Response::<String>::Success("well going our operation".into()).parse()).await.unwrap()
+            },
+            Command::Keys => transport.write_response(&Response::<String>::Success("key1,key2,key3".into()).parse()).await.unwrap(),
+            Command::Compact if capability == Capability::ReadWrite => {
+                match state.write().await.compact().await {
+                    Ok(()) => transport.write_response(&Response::<String>::Success("Compacted".into()).parse()).await.unwrap(),
+                    Err(e) => {
+                        debug!("Compaction failed: {}", e);
+                        transport.write_response(&Response::<String>::Failure.parse()).await.unwrap()
+                    }
+                }
+            },
+            Command::Compact => {
+                debug!("Rejecting compact for read-only access key");
+                transport.write_response(&Response::<String>::Failure.parse()).await.unwrap()
+            },
+            Command::Verify => {
+                let corrupted = state.read().await.verify().await;
+                debug!("Verify scan found {} corrupted entries", corrupted.len());
+                transport.write_response(&Response::<String>::Success(format!("{} corrupted", corrupted.len())).parse()).await.unwrap()
+            },
+            Command::FindNode => {
+                if request.key.len() != 32 {
+                    transport.write_response(&Response::<String>::Failure.parse()).await.unwrap()
+                } else {
+                    let mut target: cluster::NodeId = [0u8; 32];
+                    target.copy_from_slice(&request.key);
+                    let peers = state.read().await.closest_peers(target, cluster::K);
+                    transport.write_response(&Response::Success(cluster::encode_peer_list(&peers)).parse()).await.unwrap()
+                }
+            },
+            Command::Error => transport.write_response(&Response::<String>::Failure.parse()).await.unwrap(),
+        };
     }
 }
 
 
+/// Builds this node's cluster configuration from env vars, mirroring how
+/// `AccessKeys::load` reads `FFFFFF_ACCESS_KEYS`. Clustering stays disabled
+/// (returns `None`) unless `FFFFFF_CLUSTER_LOCAL_ID` is set to this node's
+/// 32-byte hex-encoded ID.
+///
+/// `FFFFFF_CLUSTER_PEERS` seeds the routing table with comma-separated
+/// `id_hex@addr` bootstrap peers. `FFFFFF_CLUSTER_REPLICATION_FACTOR`
+/// defaults to `1`. `FFFFFF_CLUSTER_ACCESS_KEY` is the key this node
+/// presents when it connects to a peer to forward a command or run
+/// `find_node`; it must also be one of that peer's `rw` entries in
+/// `FFFFFF_ACCESS_KEYS`.
+fn load_cluster_config(local_addr: std::net::SocketAddr) -> Option<cluster::ClusterConfig> {
+    let local_id_hex = std::env::var("FFFFFF_CLUSTER_LOCAL_ID").ok()?;
+    let local_id: cluster::NodeId = hex::decode(local_id_hex.trim()).ok()?.try_into().ok()?;
+
+    let bootstrap_peers = std::env::var("FFFFFF_CLUSTER_PEERS")
+        .map(|raw| cluster::parse_peer_list(&raw))
+        .unwrap_or_default();
+
+    let replication_factor = std::env::var("FFFFFF_CLUSTER_REPLICATION_FACTOR")
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(1);
+
+    let access_key = std::env::var("FFFFFF_CLUSTER_ACCESS_KEY")
+        .unwrap_or_default()
+        .into_bytes();
+
+    Some(cluster::ClusterConfig::new(
+        local_id,
+        local_addr,
+        bootstrap_peers,
+        replication_factor,
+        access_key,
+    ))
+}
+
 #[tokio::main]
 async fn main() {
     let subscriber = FmtSubscriber::builder()
@@ -151,6 +474,13 @@ async fn main() {
     debug!("Server running on {}", addr);
 
     let state = Arc::new(RwLock::new(storage::Storage::open(std::path::PathBuf::from("data.store"), 10 * 1024 * 1024 * 1024).unwrap()));
+    let access_keys = Arc::new(AccessKeys::load());
+    let require_encrypted_transport = require_encrypted_transport();
+
+    if let Some(cluster_config) = load_cluster_config(addr.parse().unwrap()) {
+        debug!("Joining cluster as {:?}", cluster_config.local_id);
+        state.write().await.join_cluster(cluster_config);
+    }
 
 
     loop {
@@ -160,8 +490,9 @@ async fn main() {
                     Ok((socket, _)) => {
                         debug!("New connection from {}", socket.peer_addr().unwrap());
                         let state = Arc::clone(&state);
+                        let access_keys = Arc::clone(&access_keys);
                         tokio::spawn(async move {
-                            handle_connection(socket, state).await;
+                            handle_connection(socket, state, access_keys, require_encrypted_transport).await;
                         });
                     }
                     Err(e) => {
@@ -176,4 +507,4 @@ async fn main() {
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,163 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tracing::debug;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const TAG_SIZE: usize = 16;
+const NONCE_SIZE: usize = 12;
+
+/// Upper bound on an encrypted frame's on-wire length, so a 4-byte length
+/// prefix can't force a multi-gigabyte allocation before a single byte of
+/// ciphertext has even been read, let alone authenticated.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+
+/// Wraps a split `TcpStream` in an authenticated encrypted transport.
+///
+/// Established via [`SecureStream::handshake`]; afterwards every message is
+/// sent as `[4-byte big-endian length][ciphertext][16-byte Poly1305 tag]`,
+/// with a per-direction key and nonce that increments as a counter for each
+/// frame in that direction.
+pub struct SecureStream {
+    read_half: OwnedReadHalf,
+    write_half: OwnedWriteHalf,
+    send_cipher: ChaCha20Poly1305,
+    recv_cipher: ChaCha20Poly1305,
+    send_base_nonce: [u8; NONCE_SIZE],
+    recv_base_nonce: [u8; NONCE_SIZE],
+    send_counter: u64,
+    recv_counter: u64,
+}
+
+impl SecureStream {
+    /// Runs the X25519 + HKDF-SHA256 handshake over a freshly accepted
+    /// connection and returns the derived encrypted transport.
+    ///
+    /// Both sides generate an ephemeral X25519 keypair and exchange their
+    /// 32-byte public key in the clear, derive a shared secret via
+    /// Diffie-Hellman, then run it through HKDF-SHA256 to produce two
+    /// independent (key, base nonce) pairs — one per direction — so the two
+    /// peers never encrypt with the same (key, nonce) pair. Which pair is
+    /// "ours to send with" is decided by comparing the two public keys: the
+    /// lower one is the `initiator`, a label both sides compute identically.
+    pub async fn handshake(
+        mut read_half: OwnedReadHalf,
+        mut write_half: OwnedWriteHalf,
+    ) -> std::io::Result<Self> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+
+        write_half.write_all(public.as_bytes()).await?;
+
+        let mut peer_public_bytes = [0u8; 32];
+        read_half.read_exact(&mut peer_public_bytes).await?;
+        let peer_public = PublicKey::from(peer_public_bytes);
+
+        let shared_secret = secret.diffie_hellman(&peer_public);
+        let we_are_initiator = public.as_bytes().as_slice() < peer_public_bytes.as_slice();
+
+        let hk = Hkdf::<Sha256>::new(None, shared_secret.as_bytes());
+        let mut okm = [0u8; 88];
+        hk.expand(b"ffffff/secure-stream", &mut okm)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "HKDF expand failed"))?;
+
+        let (initiator_to_responder, responder_to_initiator) = okm.split_at(44);
+        let (send_material, recv_material) = if we_are_initiator {
+            (initiator_to_responder, responder_to_initiator)
+        } else {
+            (responder_to_initiator, initiator_to_responder)
+        };
+
+        let send_cipher = ChaCha20Poly1305::new(Key::from_slice(&send_material[0..32]));
+        let recv_cipher = ChaCha20Poly1305::new(Key::from_slice(&recv_material[0..32]));
+
+        let mut send_base_nonce = [0u8; NONCE_SIZE];
+        send_base_nonce.copy_from_slice(&send_material[32..44]);
+        let mut recv_base_nonce = [0u8; NONCE_SIZE];
+        recv_base_nonce.copy_from_slice(&recv_material[32..44]);
+
+        debug!("Completed secure handshake as {}", if we_are_initiator { "initiator" } else { "responder" });
+
+        Ok(SecureStream {
+            read_half,
+            write_half,
+            send_cipher,
+            recv_cipher,
+            send_base_nonce,
+            recv_base_nonce,
+            send_counter: 0,
+            recv_counter: 0,
+        })
+    }
+
+    fn frame_nonce(base: &[u8; NONCE_SIZE], counter: u64) -> Nonce {
+        let mut nonce_bytes = *base;
+        let counter_bytes = counter.to_be_bytes();
+        for (b, c) in nonce_bytes[4..].iter_mut().zip(counter_bytes.iter()) {
+            *b ^= c;
+        }
+        *Nonce::from_slice(&nonce_bytes)
+    }
+
+    /// Encrypts and sends `plaintext` as a single length-prefixed frame.
+    pub async fn write_frame(&mut self, plaintext: &[u8]) -> std::io::Result<()> {
+        let nonce = Self::frame_nonce(&self.send_base_nonce, self.send_counter);
+        self.send_counter = self.send_counter.checked_add(1).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Other, "nonce counter exhausted")
+        })?;
+
+        let ciphertext = self
+            .send_cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "encryption failed"))?;
+
+        let len = ciphertext.len() as u32;
+        self.write_half.write_all(&len.to_be_bytes()).await?;
+        self.write_half.write_all(&ciphertext).await?;
+        Ok(())
+    }
+
+    /// Reads and decrypts the next frame, rejecting and closing on nonce
+    /// reuse or a Poly1305 tag failure.
+    pub async fn read_frame(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut len_buf = [0u8; 4];
+        self.read_half.read_exact(&mut len_buf).await?;
+        let len = u32::from_be_bytes(len_buf) as usize;
+
+        if len < TAG_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame shorter than the authentication tag",
+            ));
+        }
+        if len > MAX_FRAME_LEN {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame exceeds maximum length",
+            ));
+        }
+
+        let mut ciphertext = vec![0u8; len];
+        self.read_half.read_exact(&mut ciphertext).await?;
+
+        let nonce = Self::frame_nonce(&self.recv_base_nonce, self.recv_counter);
+        self.recv_counter = self.recv_counter.checked_add(1).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Other, "nonce counter exhausted")
+        })?;
+
+        self.recv_cipher
+            .decrypt(&nonce, ciphertext.as_slice())
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "tag verification failed, closing connection",
+                )
+            })
+    }
+}
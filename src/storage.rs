@@ -5,6 +5,8 @@ use tracing::debug;
 use zerocopy::{FromBytes, IntoBytes};
 use zerocopy_derive::{FromBytes, Immutable, IntoBytes, KnownLayout};
 
+use crate::cluster;
+
 #[derive(FromBytes, IntoBytes, Immutable, Debug, PartialEq, Eq)]
 #[repr(C)]
 struct Header {
@@ -15,10 +17,14 @@ struct Header {
 
     start_data: u64,
     offset_free: u64,
+
+    /// Count of entries among `keys` that are `EntryState::Deleted`, tracked
+    /// so `Shard::add` can decide when a `compact` pass is due.
+    deleted: u64,
 }
 
 impl Header {
-    const SIZE: usize = 8 * 5;
+    const SIZE: usize = 8 * 6;
 }
 
 #[derive(Debug, PartialEq, Eq, KnownLayout, Default, Immutable, IntoBytes)]
@@ -55,6 +61,9 @@ struct HashEntry {
     hash_key: [u8; 32],
     size_key: u64,
     key: [u8; 1024],
+    /// `Sha256` digest of the value bytes, checked against the data on every
+    /// read to catch partial-write tears and on-disk bit rot.
+    digest: [u8; 32],
     data_offset: u64,
     size: u64,
     state: u8,
@@ -62,15 +71,38 @@ struct HashEntry {
 }
 
 impl HashEntry {
-    const SIZE: usize = 32 + 8 + 1024 + 8 + 8 + 1 + 7;
+    const SIZE: usize = 32 + 8 + 1024 + 32 + 8 + 8 + 1 + 7;
 
     fn is_used(&self) -> bool {
         matches!(EntryState::from_u8(self.state), EntryState::Used)
     }
+
+    fn verify(&self, data: &[u8]) -> bool {
+        self.digest.as_slice() == Sha256::digest(data).as_slice()
+    }
+}
+
+/// Outcome of a `Shard::get_by_btree` lookup.
+pub enum GetResult<'a> {
+    Found(&'a [u8]),
+    NotFound,
+    /// The key was found but its stored digest no longer matches its data.
+    Corrupted,
+}
+
+/// Owned counterpart of [`GetResult`], returned once a value has crossed a
+/// shard lock or the network.
+pub enum GetOutcome {
+    Found(Vec<u8>),
+    NotFound,
+    Corrupted,
 }
 
 pub struct Storage {
     shards: Vec<RwLock<Shard>>,
+    /// Present once the node has joined a cluster; `None` keeps every key
+    /// local, matching the single-process behavior.
+    cluster: Option<cluster::ClusterConfig>,
 }
 
 pub struct Shard {
@@ -113,6 +145,7 @@ impl Storage {
                     lookup_start: Header::SIZE as u64,
                     start_data: Header::SIZE as u64 + 1024 * 1024 * 1024 * 2,
                     offset_free: Header::SIZE as u64 + 1024 * 1024 * 1024 * 2,
+                    deleted: 0,
                 };
                 let header_bytes = header.as_bytes();
                 (&mut mmap[0..Header::SIZE]).copy_from_slice(&header_bytes);
@@ -151,7 +184,152 @@ impl Storage {
             }));
         }
 
-        Ok(Storage { shards })
+        Ok(Storage { shards, cluster: None })
+    }
+
+    /// Joins a Kademlia cluster, enabling `add`/`get_by_btree`/`del` to route
+    /// keys to remote replicas instead of assuming every key is local.
+    pub fn join_cluster(&mut self, config: cluster::ClusterConfig) {
+        self.cluster = Some(config);
+    }
+
+    /// Writes `key`/`data`, replicating to the `N` closest cluster nodes
+    /// (the configured replication factor) when clustering is enabled, or
+    /// writing to the local shard only otherwise. Fails on the first
+    /// replica that can't be reached or rejects the write, rather than
+    /// silently reporting success for a replication that didn't happen.
+    pub async fn add(&self, key: &[u8], data: &[u8]) -> std::io::Result<()> {
+        let Some(cluster) = &self.cluster else {
+            return self.get_shard(key).write().await.add(key, data);
+        };
+
+        let replicas = cluster.replicas_for(key).await;
+        if replicas.is_empty() {
+            return self.get_shard(key).write().await.add(key, data);
+        }
+
+        for peer in replicas {
+            if peer.id == cluster.local_id {
+                self.get_shard(key).write().await.add(key, data)?;
+            } else {
+                let (status, _) = crate::cluster::forward_command(
+                    peer,
+                    &cluster.access_key,
+                    cluster::OPCODE_WRITE,
+                    key,
+                    data,
+                )
+                .await?;
+                if status != cluster::STATUS_OK {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("replica {:?} rejected write", peer.addr),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the value of `key` from the node(s) closest to
+    /// `Sha256(key)`, falling back to the local shard when unclustered.
+    pub async fn get_by_btree(&self, key: &[u8]) -> GetOutcome {
+        let Some(cluster) = &self.cluster else {
+            return match self.get_shard(key).read().await.get_by_btree(key) {
+                GetResult::Found(v) => GetOutcome::Found(v.to_vec()),
+                GetResult::NotFound => GetOutcome::NotFound,
+                GetResult::Corrupted => GetOutcome::Corrupted,
+            };
+        };
+
+        let replicas = cluster.replicas_for(key).await;
+        for peer in replicas {
+            if peer.id == cluster.local_id {
+                match self.get_shard(key).read().await.get_by_btree(key) {
+                    GetResult::Found(v) => return GetOutcome::Found(v.to_vec()),
+                    GetResult::Corrupted => return GetOutcome::Corrupted,
+                    GetResult::NotFound => {}
+                }
+            } else {
+                match crate::cluster::forward_command(
+                    peer,
+                    &cluster.access_key,
+                    cluster::OPCODE_READ,
+                    key,
+                    &[],
+                )
+                .await
+                {
+                    Ok((status, _)) if status == cluster::STATUS_VERIFICATION_FAILED => {
+                        return GetOutcome::Corrupted;
+                    }
+                    Ok((status, payload)) if status == cluster::STATUS_OK => {
+                        return GetOutcome::Found(payload);
+                    }
+                    Ok(_) => {}
+                    Err(e) => debug!("Replica read from {:?} failed: {}", peer.addr, e),
+                }
+            }
+        }
+
+        GetOutcome::NotFound
+    }
+
+    /// Runs `Shard::verify` across every shard and returns the corrupted
+    /// keys found, prefixed by which shard they live in.
+    pub async fn verify(&self) -> Vec<(usize, Vec<u8>)> {
+        let mut corrupted = Vec::new();
+        for (shard_id, shard) in self.shards.iter().enumerate() {
+            for key in shard.read().await.verify() {
+                corrupted.push((shard_id, key));
+            }
+        }
+        corrupted
+    }
+
+    /// Deletes `key` from every replica that holds it. Fails on the first
+    /// replica that can't be reached or rejects the delete, rather than
+    /// silently reporting success for a replication that didn't happen.
+    pub async fn del(&self, key: &[u8]) -> std::io::Result<()> {
+        let Some(cluster) = &self.cluster else {
+            return self.get_shard(key).write().await.del(key);
+        };
+
+        let replicas = cluster.replicas_for(key).await;
+
+        for peer in replicas {
+            if peer.id == cluster.local_id {
+                self.get_shard(key).write().await.del(key)?;
+            } else {
+                let (status, _) = crate::cluster::forward_command(
+                    peer,
+                    &cluster.access_key,
+                    cluster::OPCODE_DELETE,
+                    key,
+                    &[],
+                )
+                .await?;
+                if status != cluster::STATUS_OK {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Other,
+                        format!("replica {:?} rejected delete", peer.addr),
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Peers from the local routing table closest to `target`, for the
+    /// server's `find_node` RPC handler. Empty if this node hasn't joined a
+    /// cluster.
+    pub fn closest_peers(&self, target: cluster::NodeId, count: usize) -> Vec<cluster::Peer> {
+        match &self.cluster {
+            Some(cluster) => cluster.routing_table.closest(&target, count),
+            None => Vec::new(),
+        }
     }
 
     pub fn get_shard(&self, key: &[u8]) -> &RwLock<Shard> {
@@ -169,9 +347,23 @@ impl Storage {
         }
         Ok(())
     }
+
+    /// Runs `Shard::compact` on every shard, reclaiming space leaked by
+    /// deletes and overwrites regardless of whether the automatic threshold
+    /// has been crossed.
+    pub async fn compact(&self) -> std::io::Result<()> {
+        for shard in &self.shards {
+            shard.write().await.compact()?;
+        }
+        Ok(())
+    }
 }
 
 impl Shard {
+    /// Ratio of deleted-to-live entries above which `add` triggers an
+    /// automatic [`Shard::compact`] pass.
+    const COMPACTION_THRESHOLD: f64 = 0.5;
+
     pub fn add(&mut self, key: &[u8], data: &[u8]) -> std::io::Result<()> {
         let position = self.header.offset_free;
 
@@ -190,6 +382,7 @@ impl Shard {
             hash_key: Sha256::digest(key).into(),
             size_key: key.len() as u64,
             key: buf,
+            digest: Sha256::digest(data).into(),
             data_offset: position,
             size: data.len() as u64,
             state: EntryState::Used.to_u8(),
@@ -215,6 +408,102 @@ impl Shard {
 
         let _ = self.mmap.flush_async();
 
+        if self.should_compact() {
+            // The write above already landed durably; a failure here is the
+            // background compaction's problem, not this add's, so it's
+            // logged rather than propagated as a failed write.
+            if let Err(e) = self.compact() {
+                debug!("Automatic compaction after add failed: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the ratio of deleted-to-live entries has crossed
+    /// [`Shard::COMPACTION_THRESHOLD`], warranting an automatic `compact`.
+    fn should_compact(&self) -> bool {
+        let live = self.header.keys.saturating_sub(self.header.deleted);
+        if live == 0 {
+            return false;
+        }
+        (self.header.deleted as f64 / live as f64) > Self::COMPACTION_THRESHOLD
+    }
+
+    /// Reclaims space leaked by `del` and by overwrites: walks the lookup
+    /// table, keeps only `is_used()` entries that `index` still points at
+    /// (dropping entries superseded by an overwrite), and copies them plus the data
+    /// they reference into a fresh region past the current `offset_free` —
+    /// the old lookup table and data are left untouched.
+    ///
+    /// Rebuilds `index` from the surviving `hash_key` -> offset pairs and
+    /// only swaps `lookup_start`/`start_data`/`offset_free` over to the new
+    /// region in a single header write at the end, so a crash mid-copy
+    /// leaves the header still pointing at the untouched, consistent old
+    /// layout.
+    pub fn compact(&mut self) -> std::io::Result<()> {
+        let mut live_entries = Vec::with_capacity(self.header.keys as usize);
+        let mut offset = self.header.lookup_start;
+
+        for _ in 0..self.header.keys {
+            let entry = HashEntry::read_from_bytes(
+                &self.mmap[offset as usize..(offset + HashEntry::SIZE as u64) as usize],
+            );
+
+            if let Ok(entry) = entry {
+                if entry.is_used() && self.index.index.get(&entry.hash_key) == Some(&offset) {
+                    let data = self.mmap[entry.data_offset as usize
+                        ..(entry.data_offset + entry.size) as usize]
+                        .to_vec();
+                    live_entries.push((entry, data));
+                }
+            }
+            offset += HashEntry::SIZE as u64;
+        }
+
+        let new_lookup_start = self.header.offset_free;
+        let new_start_data = new_lookup_start + live_entries.len() as u64 * HashEntry::SIZE as u64;
+        let live_bytes: u64 = live_entries.iter().map(|(entry, _)| entry.size).sum();
+        let new_offset_free = new_start_data + live_bytes;
+
+        if new_offset_free > self.header.total_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::OutOfMemory,
+                "not enough free space left to compact into a fresh region",
+            ));
+        }
+
+        let mut data_cursor = new_start_data;
+        let mut lookup_cursor = new_lookup_start;
+        let mut rebuilt_index = Vec::with_capacity(live_entries.len());
+
+        for (mut entry, data) in live_entries {
+            self.mmap[data_cursor as usize..(data_cursor + entry.size) as usize]
+                .copy_from_slice(&data);
+            entry.data_offset = data_cursor;
+            data_cursor += entry.size;
+
+            self.mmap[lookup_cursor as usize..(lookup_cursor + HashEntry::SIZE as u64) as usize]
+                .copy_from_slice(&entry.as_mut_bytes());
+            rebuilt_index.push((entry.hash_key, lookup_cursor));
+            lookup_cursor += HashEntry::SIZE as u64;
+        }
+
+        self.header.keys = rebuilt_index.len() as u64;
+        self.header.deleted = 0;
+        self.header.lookup_start = new_lookup_start;
+        self.header.start_data = new_start_data;
+        self.header.offset_free = new_offset_free;
+
+        let header_bytes = self.header.as_bytes();
+        self.mmap[0..Header::SIZE].copy_from_slice(&header_bytes);
+
+        self.index = Index::new(rebuilt_index);
+
+        self.mmap.flush()?;
+
+        debug!("Compacted shard: {} live keys", self.header.keys);
+
         Ok(())
     }
 
@@ -223,10 +512,8 @@ impl Shard {
         self.file.sync_all()
     }
 
-    pub fn get_by_btree(&self, key: &[u8]) -> Option<&[u8]> {
-        let str_key = str::from_utf8(key).unwrap();
-
-        match self.index.get(str_key) {
+    pub fn get_by_btree(&self, key: &[u8]) -> GetResult<'_> {
+        match self.index.get(key) {
             Some(offset) => {
                 let entry = HashEntry::read_from_bytes(
                     &self.mmap[*offset as usize..(*offset + HashEntry::SIZE as u64) as usize],
@@ -234,16 +521,46 @@ impl Shard {
                 .unwrap();
                 let data_offset = entry.data_offset;
                 let data_size = entry.size;
-                debug!("Found key '{}' - {:?}", str_key, entry);
-                Some(&self.mmap[data_offset as usize..(data_offset + data_size) as usize])
+                debug!("Found key '{:?}' - {:?}", key, entry);
+                let data = &self.mmap[data_offset as usize..(data_offset + data_size) as usize];
+
+                if entry.verify(data) {
+                    GetResult::Found(data)
+                } else {
+                    debug!("Checksum mismatch for key '{:?}'", key);
+                    GetResult::Corrupted
+                }
+            }
+            _ => GetResult::NotFound,
+        }
+    }
+
+    /// Scans every live entry and reports the keys whose stored digest no
+    /// longer matches their data, e.g. due to a torn write or bit rot.
+    pub fn verify(&self) -> Vec<Vec<u8>> {
+        let mut corrupted = Vec::new();
+        let mut offset = self.header.lookup_start;
+
+        for _ in 0..self.header.keys {
+            if let Ok(entry) = HashEntry::read_from_bytes(
+                &self.mmap[offset as usize..(offset + HashEntry::SIZE as u64) as usize],
+            ) {
+                if entry.is_used() {
+                    let data = &self.mmap
+                        [entry.data_offset as usize..(entry.data_offset + entry.size) as usize];
+                    if !entry.verify(data) {
+                        corrupted.push(entry.key[..entry.size_key as usize].to_vec());
+                    }
+                }
             }
-            _ => None,
+            offset += HashEntry::SIZE as u64;
         }
+
+        corrupted
     }
 
     pub fn del(&mut self, key: &[u8]) -> std::io::Result<()> {
-        let k = str::from_utf8(key).unwrap();
-        match self.index.get(k) {
+        match self.index.get(key) {
             Some(offset) => {
                 let mut entry = HashEntry::read_from_bytes(
                     &self.mmap[*offset as usize..(*offset + HashEntry::SIZE as u64) as usize],
@@ -252,7 +569,12 @@ impl Shard {
                 entry.state = EntryState::Deleted.to_u8();
                 self.mmap[*offset as usize..(*offset + HashEntry::SIZE as u64) as usize]
                     .copy_from_slice(&entry.as_mut_bytes());
-                self.index.del(k);
+                self.index.del(key);
+
+                self.header.deleted += 1;
+                let header_bytes = self.header.as_bytes();
+                self.mmap[0..Header::SIZE].copy_from_slice(&header_bytes);
+
                 self.mmap.flush_async()?;
                 Ok(())
             }
@@ -281,7 +603,7 @@ impl Index {
         self.index.insert(*key, value);
     }
 
-    fn get(&self, key: &str) -> Option<&u64> {
+    fn get(&self, key: &[u8]) -> Option<&u64> {
         let mut buf = [0; 32];
         let f = Sha256::digest(key);
         buf.copy_from_slice(&f);
@@ -289,7 +611,7 @@ impl Index {
         self.index.get(&buf)
     }
 
-    fn del(&mut self, key: &str) {
+    fn del(&mut self, key: &[u8]) {
         let mut buf = [0; 32];
         let f = Sha256::digest(key);
         buf.copy_from_slice(&f);